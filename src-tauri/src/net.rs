@@ -0,0 +1,590 @@
+//! HTTP client setup and error formatting shared by every test.
+
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use reqwest::Client;
+use serde::Serialize;
+
+/// Connection settings common to all tests. Per-request details (auth, headers) are applied
+/// by the caller; see `RequestExtras`.
+#[derive(Clone)]
+pub(crate) struct ClientOptions {
+    /// Whole-request timeout, clamped to 1..=120 seconds.
+    pub timeout_ms: u64,
+    /// Time allowed for the TCP connect (and proxy handshake), at most `timeout_ms`. Kept well
+    /// below the OS SYN timeout so an unreachable server fails as a retryable connect timeout
+    /// instead of running into the request timeout.
+    pub connect_timeout_ms: u64,
+    /// 0 disables following redirects.
+    pub max_redirects: usize,
+    /// Honor `HTTP(S)_PROXY` and friends from the environment.
+    pub use_system_proxy: bool,
+    /// Explicit proxy URL; see `parse_proxy` for the accepted schemes.
+    pub proxy: Option<String>,
+    /// Local IP the sockets are bound to.
+    pub local_address: Option<IpAddr>,
+    /// PEM file of extra trusted root certificates.
+    pub ca_cert_path: Option<String>,
+    /// Callers are expected to warn the user when they set this.
+    pub danger_accept_invalid_certs: bool,
+    /// `auto` (or unset), `http1`, `http2`, or `http3` in builds with the `http3` feature.
+    pub http_version: Option<String>,
+    /// Decode gzip/brotli/deflate bodies so byte counts are what the application receives.
+    /// Off by default: then nothing is decoded and servers are asked not to compress.
+    pub decompress: bool,
+    /// Idle connections kept per host; 0 makes every request open a new connection.
+    pub pool_max_idle_per_host: usize,
+    /// TCP keep-alive interval; `None` keeps reqwest's default of 15 seconds.
+    pub tcp_keepalive_secs: Option<u64>,
+    /// Disable Nagle's algorithm so small requests aren't held back; on by default.
+    pub tcp_nodelay: bool,
+    /// Hosts pinned to an address instead of being looked up; the ports are ignored, as the
+    /// URL's port is what gets connected to.
+    pub resolve: Vec<(String, SocketAddr)>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 30_000,
+            connect_timeout_ms: 10_000,
+            max_redirects: 10,
+            use_system_proxy: true,
+            proxy: None,
+            local_address: None,
+            ca_cert_path: None,
+            danger_accept_invalid_certs: false,
+            http_version: None,
+            decompress: false,
+            pool_max_idle_per_host: usize::MAX,
+            tcp_keepalive_secs: None,
+            tcp_nodelay: true,
+            resolve: Vec::new(),
+        }
+    }
+}
+
+/// Builds the client a test runs on. The error is ready to show to the user.
+pub(crate) fn build_client(opts: ClientOptions) -> Result<Client, String> {
+    let ClientOptions {
+        timeout_ms,
+        connect_timeout_ms,
+        max_redirects,
+        use_system_proxy,
+        proxy,
+        local_address,
+        ca_cert_path,
+        danger_accept_invalid_certs,
+        http_version,
+        decompress,
+        pool_max_idle_per_host,
+        tcp_keepalive_secs,
+        tcp_nodelay,
+        resolve,
+    } = opts;
+
+    let timeout_ms = timeout_ms.clamp(1_000, 120_000);
+    let mut builder = Client::builder()
+        .timeout(Duration::from_millis(timeout_ms))
+        .connect_timeout(Duration::from_millis(
+            connect_timeout_ms.clamp(1, timeout_ms),
+        ))
+        .redirect(redirect_policy(max_redirects))
+        .user_agent("SpeedHive/0.1 (Tauri)")
+        .pool_max_idle_per_host(pool_max_idle_per_host)
+        .tcp_nodelay(tcp_nodelay);
+
+    // Passing `None` would turn keep-alive off rather than leave reqwest's default.
+    if let Some(secs) = tcp_keepalive_secs {
+        builder = builder.tcp_keepalive(Duration::from_secs(secs));
+    }
+
+    // Byte counts should normally be what crossed the network: never decompress, and ask
+    // servers not to compress in the first place.
+    if !decompress {
+        builder = builder.no_gzip().no_brotli().no_deflate().default_headers(
+            reqwest::header::HeaderMap::from_iter([(
+                reqwest::header::ACCEPT_ENCODING,
+                reqwest::header::HeaderValue::from_static("identity"),
+            )]),
+        );
+    }
+
+    // Environment proxy detection is on by default; this has to run before an explicit
+    // proxy is added because `no_proxy()` clears every configured proxy.
+    if !use_system_proxy {
+        builder = builder.no_proxy();
+    }
+
+    if let Some(ip) = local_address {
+        builder = builder.local_address(ip);
+    }
+
+    for (host, addr) in &resolve {
+        builder = builder.resolve(host, *addr);
+    }
+
+    if let Some(proxy) = proxy.as_deref().filter(|p| !p.trim().is_empty()) {
+        builder = builder.proxy(parse_proxy(proxy)?);
+    }
+
+    if let Some(path) = ca_cert_path.as_deref().filter(|p| !p.trim().is_empty()) {
+        for cert in load_ca_certificates(path)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    if danger_accept_invalid_certs {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    builder = match http_version.as_deref().map(str::trim) {
+        None | Some("") | Some("auto") => builder,
+        Some("http1") => builder.http1_only(),
+        // Prior knowledge skips ALPN, so a server without HTTP/2 fails instead of
+        // quietly falling back and skewing a protocol comparison.
+        Some("http2") => builder.http2_prior_knowledge(),
+        #[cfg(feature = "http3")]
+        Some("http3") => builder.http3_prior_knowledge(),
+        #[cfg(not(feature = "http3"))]
+        Some("http3") => {
+            return Err("HTTP/3 support was not built into this version of SpeedHive".to_string());
+        }
+        Some(other) => {
+            return Err(format!(
+                "Invalid http_version \"{other}\" (expected \"auto\", \"http1\", \"http2\" or \"http3\")"
+            ));
+        }
+    };
+
+    builder
+        .build()
+        .map_err(|err| format!("Failed to build HTTP client:\n{}", format_error_chain(&err)))
+}
+
+/// Formats an error followed by each of its sources on a `caused by:` line; reqwest's own
+/// `Display` usually hides the part that explains what went wrong.
+pub(crate) fn format_error_chain(err: &dyn Error) -> String {
+    let mut out = err.to_string();
+    let mut cur = err.source();
+    while let Some(e) = cur {
+        out.push_str("\ncaused by: ");
+        out.push_str(&e.to_string());
+        cur = e.source();
+    }
+    out
+}
+
+/// What kind of failure an error is, so the UI can react to it (e.g. suggest checking the
+/// proxy settings) without parsing the message.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ErrorKind {
+    Dns,
+    Connect,
+    Tls,
+    Timeout,
+    HttpStatus,
+    Proxy,
+    /// A test with the same id is still running, so this one wasn't started.
+    AlreadyRunning,
+    Other,
+}
+
+/// Classifies an error from its source chain. reqwest's own predicates come first; the
+/// layers below don't have typed errors for DNS or proxy failures, so those are recognised
+/// by their messages.
+pub(crate) fn classify_error(err: &(dyn Error + 'static)) -> ErrorKind {
+    if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+        if err.is_timeout() {
+            return ErrorKind::Timeout;
+        }
+        if err.is_status() {
+            return ErrorKind::HttpStatus;
+        }
+    }
+
+    let mut connect = false;
+    let mut cur = Some(err);
+    while let Some(e) = cur {
+        if e.downcast_ref::<native_tls::Error>().is_some() {
+            return ErrorKind::Tls;
+        }
+        #[cfg(feature = "http3")]
+        if e.downcast_ref::<rustls::Error>().is_some() {
+            return ErrorKind::Tls;
+        }
+        if let Some(io) = e.downcast_ref::<std::io::Error>() {
+            // rustls errors arrive wrapped in an io::Error, whose `source()` skips them.
+            #[cfg(feature = "http3")]
+            if io
+                .get_ref()
+                .is_some_and(|inner| inner.downcast_ref::<rustls::Error>().is_some())
+            {
+                return ErrorKind::Tls;
+            }
+            match io.kind() {
+                std::io::ErrorKind::TimedOut => return ErrorKind::Timeout,
+                std::io::ErrorKind::ConnectionRefused
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted => connect = true,
+                _ => {}
+            }
+        }
+        // A reqwest error's own message only names the stage, followed by the request URL,
+        // which may well contain "proxy" or "tls"; the details are in its sources.
+        let message = if e.is::<reqwest::Error>() {
+            String::new()
+        } else {
+            e.to_string().to_ascii_lowercase()
+        };
+        if message.contains("dns error") || message.contains("failed to lookup address") {
+            return ErrorKind::Dns;
+        }
+        if message.contains("proxy") || message.contains("socks") {
+            return ErrorKind::Proxy;
+        }
+        if message.contains("certificate") || message.contains("tls") {
+            return ErrorKind::Tls;
+        }
+        connect |= e
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(reqwest::Error::is_connect);
+        cur = e.source();
+    }
+
+    if connect {
+        ErrorKind::Connect
+    } else {
+        ErrorKind::Other
+    }
+}
+
+/// Whether a connection reset (or a write to an already reset socket) is somewhere in the
+/// error's source chain.
+pub(crate) fn is_connection_reset(err: &(dyn Error + 'static)) -> bool {
+    let mut cur = Some(err);
+    while let Some(e) = cur {
+        if let Some(io) = e.downcast_ref::<std::io::Error>() {
+            if matches!(
+                io.kind(),
+                std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::BrokenPipe
+            ) {
+                return true;
+            }
+        }
+        cur = e.source();
+    }
+    false
+}
+
+/// Looks `host` up through a DNS-over-HTTPS resolver speaking the JSON API (Cloudflare's and
+/// Google's both do). `ipv6` picks AAAA over A records; `None` asks for A first and falls
+/// back to AAAA. The error is ready to show to the user.
+pub(crate) async fn resolve_doh(
+    resolver: &str,
+    host: &str,
+    ipv6: Option<bool>,
+) -> Result<IpAddr, String> {
+    let client = build_client(ClientOptions {
+        timeout_ms: 5_000,
+        ..Default::default()
+    })?;
+    let record_types: &[(&str, u64)] = match ipv6 {
+        Some(true) => &[("AAAA", 28)],
+        Some(false) => &[("A", 1)],
+        None => &[("A", 1), ("AAAA", 28)],
+    };
+
+    for &(name, code) in record_types {
+        let response = client
+            .get(resolver)
+            .query(&[("name", host), ("type", name)])
+            .header(reqwest::header::ACCEPT, "application/dns-json")
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|err| format!("DoH query failed:\n{}", format_error_chain(&err)))?;
+        let body: serde_json::Value = response
+            .json()
+            .await
+            .map_err(|err| format!("Invalid DoH response:\n{}", format_error_chain(&err)))?;
+        // CNAMEs come back as answers of their own, so only the records of the asked-for
+        // type are addresses.
+        let ip = body["Answer"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|answer| answer["type"].as_u64() == Some(code))
+            .find_map(|answer| answer["data"].as_str()?.parse::<IpAddr>().ok());
+        if let Some(ip) = ip {
+            return Ok(ip);
+        }
+    }
+    Err(format!("The DoH resolver returned no address for {host}"))
+}
+
+/// Parses the `local_address` option into the IP the test's sockets are bound to.
+pub(crate) fn parse_local_address(addr: &str) -> Result<IpAddr, String> {
+    addr.trim().parse().map_err(|_| {
+        format!("Invalid local_address \"{addr}\" (expected an IP address such as 192.168.1.20)")
+    })
+}
+
+/// Builds a proxy for every request from `http://`, `https://`, `socks5://` or `socks5h://`
+/// URLs. Credentials embedded in the URL are picked up by reqwest as proxy auth; the URL
+/// itself is never echoed back so they don't leak into the UI.
+fn parse_proxy(proxy: &str) -> Result<reqwest::Proxy, String> {
+    let proxy = proxy.trim();
+    let scheme = proxy
+        .split_once("://")
+        .map(|(scheme, _)| scheme.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match scheme.as_str() {
+        "http" | "https" => {}
+        "socks5" | "socks5h" if cfg!(feature = "socks") => {}
+        "socks5" | "socks5h" => {
+            return Err("SOCKS5 proxies are not supported by this build".to_string());
+        }
+        "" => return Err("Invalid proxy URL: missing scheme (e.g. socks5://)".to_string()),
+        other => {
+            return Err(format!(
+                "Unsupported proxy scheme \"{other}\" (expected http, https, socks5 or socks5h)"
+            ));
+        }
+    }
+
+    reqwest::Proxy::all(proxy)
+        .map_err(|err| format!("Invalid proxy URL:\n{}", format_error_chain(&err)))
+}
+
+fn redirect_policy(max_redirects: usize) -> reqwest::redirect::Policy {
+    match max_redirects {
+        0 => reqwest::redirect::Policy::none(),
+        n => reqwest::redirect::Policy::limited(n),
+    }
+}
+
+/// Reads every certificate from a PEM bundle so they can be trusted as extra roots.
+fn load_ca_certificates(path: &str) -> Result<Vec<reqwest::Certificate>, String> {
+    let pem = std::fs::read(path)
+        .map_err(|err| format!("Failed to read CA certificate file {path}:\n{err}"))?;
+    let certs = reqwest::Certificate::from_pem_bundle(&pem)
+        .map_err(|err| format!("Failed to parse CA certificate file {path}:\n{err}"))?;
+    if certs.is_empty() {
+        return Err(format!("No PEM certificates found in {path}"));
+    }
+    Ok(certs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+    use std::sync::{Mutex, PoisonError};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Answers one request on `listener` with `200 ok` and returns its head and the peer address.
+    async fn answer_once(listener: tokio::net::TcpListener) -> (String, SocketAddr) {
+        let (mut stream, peer) = listener.accept().await.unwrap();
+        let mut head = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed before the request head");
+            head.extend_from_slice(&buf[..n]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok")
+            .await
+            .unwrap();
+        (String::from_utf8_lossy(&head).into_owned(), peer)
+    }
+
+    #[tokio::test]
+    async fn explicit_proxy_is_used_without_the_system_proxy() {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let proxy = format!("http://{}", listener.local_addr().unwrap());
+        let served = tokio::spawn(answer_once(listener));
+
+        let client = build_client(ClientOptions {
+            use_system_proxy: false,
+            proxy: Some(proxy),
+            ..Default::default()
+        })
+        .unwrap();
+        // `.invalid` never resolves, so only the proxy can have answered.
+        let res = client
+            .get("http://speedhive.invalid/probe")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.text().await.unwrap(), "ok");
+
+        let (head, _) = served.await.unwrap();
+        assert!(
+            head.starts_with("GET http://speedhive.invalid/probe HTTP/1.1\r\n"),
+            "{head}"
+        );
+    }
+
+    /// Held while a test changes proxy variables, which every client built meanwhile reads.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    #[tokio::test]
+    async fn system_proxy_comes_from_the_environment() {
+        let proxy = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let proxy_url = format!("http://{}", proxy.local_addr().unwrap());
+        let direct = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let direct_addr = direct.local_addr().unwrap();
+        let via_proxy = tokio::spawn(answer_once(proxy));
+        let via_direct = tokio::spawn(answer_once(direct));
+
+        // Both clients would connect straight to `direct`; only the proxy can divert them.
+        let client = |use_system_proxy| {
+            build_client(ClientOptions {
+                use_system_proxy,
+                resolve: vec![("speedhive.test".to_string(), direct_addr)],
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let (with_system, without_system) = {
+            let _env = ENV_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+            let saved: Vec<_> = ["HTTP_PROXY", "NO_PROXY"]
+                .map(|name| (name, std::env::var_os(name)))
+                .into();
+            std::env::set_var("HTTP_PROXY", &proxy_url);
+            // Tests building clients in the meantime still reach their loopback servers.
+            std::env::set_var("NO_PROXY", "127.0.0.1,localhost,::1");
+            let clients = (client(true), client(false));
+            for (name, value) in saved {
+                match value {
+                    Some(value) => std::env::set_var(name, value),
+                    None => std::env::remove_var(name),
+                }
+            }
+            clients
+        };
+
+        let url = format!("http://speedhive.test:{}/probe", direct_addr.port());
+        for client in [with_system, without_system] {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.text().await.unwrap(), "ok");
+        }
+        let (head, _) = via_proxy.await.unwrap();
+        assert!(
+            head.starts_with(&format!("GET {url} HTTP/1.1\r\n")),
+            "{head}"
+        );
+        let (head, _) = via_direct.await.unwrap();
+        assert!(head.starts_with("GET /probe HTTP/1.1\r\n"), "{head}");
+    }
+
+    #[test]
+    fn local_address_must_be_an_ip() {
+        assert_eq!(
+            parse_local_address(" 127.0.0.1 "),
+            Ok(IpAddr::V4(Ipv4Addr::LOCALHOST))
+        );
+        assert_eq!(parse_local_address("::1"), Ok("::1".parse().unwrap()));
+        for bad in ["", "localhost", "127.0.0.1:8080", "256.0.0.1", "eth0"] {
+            assert!(parse_local_address(bad).is_err(), "{bad:?} was accepted");
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_leave_from_the_local_address() {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let served = tokio::spawn(answer_once(listener));
+
+        // All of 127.0.0.0/8 is loopback, so a source other than the default 127.0.0.1 shows
+        // that the bind took effect.
+        let source = Ipv4Addr::new(127, 0, 0, 2);
+        let client = build_client(ClientOptions {
+            use_system_proxy: false,
+            local_address: Some(IpAddr::V4(source)),
+            ..Default::default()
+        })
+        .unwrap();
+        client.get(url).send().await.unwrap();
+
+        let (_, peer) = served.await.unwrap();
+        assert_eq!(peer.ip(), IpAddr::V4(source));
+    }
+
+    #[tokio::test]
+    async fn request_url_does_not_decide_the_kind() {
+        // Bound and dropped, so nothing is listening on the port.
+        let port = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client = build_client(ClientOptions {
+            use_system_proxy: false,
+            resolve: vec![(
+                "proxy-tls-certificate.example".to_string(),
+                (Ipv4Addr::LOCALHOST, port).into(),
+            )],
+            ..Default::default()
+        })
+        .unwrap();
+        let err = client
+            .get(format!(
+                "http://proxy-tls-certificate.example:{port}/socks/dns error"
+            ))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(classify_error(&err), ErrorKind::Connect),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_server_fails_as_a_connect_timeout() {
+        // A listener whose accept queue is full: the kernel drops further SYNs, the way a
+        // firewall blackholing the port would.
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let _listener = socket.listen(0).unwrap();
+        let mut queued = Vec::new();
+        for _ in 0..4 {
+            let connect = tokio::net::TcpStream::connect(addr);
+            match tokio::time::timeout(Duration::from_millis(200), connect).await {
+                Ok(Ok(stream)) => queued.push(stream),
+                _ => break,
+            }
+        }
+
+        let client = build_client(ClientOptions {
+            connect_timeout_ms: 300,
+            use_system_proxy: false,
+            ..Default::default()
+        })
+        .unwrap();
+        let err = client
+            .get(format!("http://{addr}/"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.is_connect() && err.is_timeout(), "{err:?}");
+    }
+}
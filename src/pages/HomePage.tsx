@@ -28,11 +28,26 @@ type DownloadSpeedEvent =
     }
   | {
       event: "progress";
-      data: { elapsed_ms: number; bytes: number; mbps: number };
+      data: {
+        url: string;
+        elapsed_ms: number;
+        bytes: number;
+        instant_mbps: number;
+        avg_mbps: number;
+      };
     }
   | {
       event: "finished";
-      data: { elapsed_ms: number; bytes: number; avg_mbps: number };
+      data: {
+        url: string;
+        elapsed_ms: number;
+        bytes: number;
+        avg_mbps: number;
+      };
+    }
+  | {
+      event: "cancelled";
+      data: { elapsed_ms: number; bytes: number };
     }
   | {
       event: "error";
@@ -46,7 +61,12 @@ type UploadSpeedEvent =
     }
   | {
       event: "progress";
-      data: { elapsed_ms: number; bytes: number; mbps: number };
+      data: {
+        elapsed_ms: number;
+        bytes: number;
+        instant_mbps: number;
+        avg_mbps: number;
+      };
     }
   | {
       event: "finished";
@@ -191,7 +211,7 @@ export default function HomePage() {
       if (!message) return;
       switch (message.event) {
         case "progress":
-          setDownloadMbps(message.data.mbps);
+          setDownloadMbps(message.data.instant_mbps);
           break;
         case "finished":
           setDownloadPhase("finished");
@@ -209,7 +229,8 @@ export default function HomePage() {
     // Example URL; configurable later.
     // Because the download happens in Rust, CORS is not a concern here.
     const url = "https://speed.hetzner.de/100MB.bin";
-    await invoke("download_speed_test", { url, durationMs, onEvent });
+    const testId = crypto.randomUUID();
+    await invoke("download_speed_test", { testId, url, durationMs, onEvent });
   }
 
   async function startUploadTest() {
@@ -232,7 +253,7 @@ export default function HomePage() {
         case "progress":
           // Ignore progress events after finished (they may arrive late due to async timing)
           if (!finishedRef.current) {
-            setUploadMbps(message.data.mbps);
+            setUploadMbps(message.data.instant_mbps);
           }
           break;
         case "finished":
@@ -253,7 +274,9 @@ export default function HomePage() {
 
     // Endpoint must accept POST uploads. Configurable later.
     const url = "https://postman-echo.com/post";
+    const testId = crypto.randomUUID();
     await invoke("upload_speed_test", {
+      testId,
       url,
       durationMs,
       chunkSize: uploadChunkSize,